@@ -1,16 +1,26 @@
+mod proxy_protocol;
 mod socks;
+mod tls;
 
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::env;
+use std::fs;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::mem::size_of_val;
 use std::net::AddrParseError;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::os::fd::FromRawFd;
 use std::os::fd::RawFd;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
 
 use clap::Parser;
 use libc::c_int;
@@ -24,21 +34,26 @@ use libc::SO_DOMAIN;
 use socks::AddressType;
 use socks::REP_SUCCEEDED;
 use socks::{
-    read_client_hello, read_socks_request, COMMAND_CONNECT, NO_ACCEPTABLE_AUTHENTICATION,
-    NO_AUTHENTICATION, SOCKS_VERSION5,
+    read_client_hello, read_socks_request, read_username_password, COMMAND_CONNECT,
+    NO_ACCEPTABLE_AUTHENTICATION, NO_AUTHENTICATION, SOCKS_VERSION5,
+    USERNAME_PASSWORD_AUTHENTICATION,
 };
 use tokio::fs::remove_file;
 use tokio::io::copy_bidirectional;
 use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWrite;
 use tokio::net::unix::uid_t;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
 use tokio::signal;
+use tokio::time::{timeout, Duration};
 use tokio::{
     io::AsyncWriteExt,
-    net::{UnixListener, UnixStream},
+    net::{UnixDatagram, UnixListener, UnixStream},
 };
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 use tracing::instrument;
@@ -46,9 +61,15 @@ use tracing::{debug, info};
 
 use crate::socks::SocksRequestAddress;
 use crate::socks::REP_ADDRESS_TYPE_NOT_SUPPORTED;
+use crate::socks::{
+    build_udp_datagram_header, parse_udp_datagram, COMMAND_RESOLVE, COMMAND_RESOLVE_PTR,
+    COMMAND_UDP_ASSOCIATE,
+};
 use crate::socks::{REP_COMMAND_NOT_SUPPORTED, REP_CONNECTION_NOT_ALLOWED, REP_HOST_NOT_REACHABLE};
+use crate::proxy_protocol::{read_proxy_protocol_header, UnixCredentials};
+use crate::tls::parse_client_hello_sni;
 
-trait GenericStream: AsyncRead + AsyncWrite {
+trait GenericStream: AsyncRead + AsyncWrite + Unpin + Send {
     fn get_uid(&self) -> Result<uid_t, std::io::Error>;
 }
 
@@ -65,6 +86,62 @@ impl GenericStream for TcpStream {
     }
 }
 
+impl GenericStream for KcpStream {
+    fn get_uid(&self) -> Result<uid_t, std::io::Error> {
+        // Not implemented yet:
+        return Err(Error::from(ErrorKind::Other));
+    }
+}
+
+/// A TCP connection whose real client identity was recovered from a leading
+/// PROXY protocol v2 header (see `proxy_protocol`), rather than from the
+/// connection's own peer address, which would just be the load balancer's.
+struct ProxiedTcpStream {
+    inner: TcpStream,
+    unix_credentials: Option<UnixCredentials>,
+}
+
+impl AsyncRead for ProxiedTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl GenericStream for ProxiedTcpStream {
+    fn get_uid(&self) -> Result<uid_t, std::io::Error> {
+        match self.unix_credentials {
+            Some(credentials) => Ok(credentials.uid),
+            None => self.inner.get_uid(),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 struct CliArguments {
     #[arg(name = "SOCKET")]
@@ -73,11 +150,121 @@ struct CliArguments {
     directory: String,
     #[clap(long = "allowed-uids", value_delimiter = ',')]
     allowed_uids: Option<Vec<uid_t>>,
+    /// Path to a file of `user:password` lines used for SOCKS5 username/password
+    /// authentication (RFC 1929). When set, clients must authenticate with one
+    /// of these credentials instead of `NO_AUTHENTICATION`.
+    #[arg(long = "auth-file")]
+    auth_file: Option<String>,
+    /// Upstream routing rule `PATTERN=TARGET`, evaluated in order against the
+    /// requested domain. `PATTERN` is either `*`, a `*`-prefixed suffix match
+    /// (e.g. `*.onion`), or an exact domain. `TARGET` is one of `unix-dir`
+    /// (the directory, the default), `tcp-connect`, or `deny`. May be given
+    /// multiple times; defaults to `*=unix-dir` when absent.
+    #[clap(long = "route")]
+    routes: Vec<String>,
+    /// TCP address of an SNI-based TLS passthrough endpoint. Instead of
+    /// speaking SOCKS, connections are routed by their TLS ClientHello's SNI
+    /// to the Unix socket `{sni}_443` in `directory`; `--route` rules do not
+    /// apply to them. May be given multiple times.
+    #[clap(long = "tls-sni-endpoint")]
+    tls_sni_endpoints: Vec<String>,
+    /// TCP address on which to expect a leading HAProxy PROXY protocol v2
+    /// header, used to recover the real client address (and, for AF_UNIX,
+    /// embedded Unix credentials) behind a load balancer. Must also be
+    /// listed as a `SOCKET`. May be given multiple times.
+    ///
+    /// WARNING: the header is trusted verbatim, including the private
+    /// `uid`/`gid` TLV, which `--allowed-uids` relies on. Anyone who can
+    /// open a direct TCP connection to this endpoint can forge it and
+    /// impersonate any client address or uid/gid. Only bind this to an
+    /// address that is reachable exclusively from your trusted load
+    /// balancer, never directly from untrusted clients.
+    #[clap(long = "proxy-protocol")]
+    proxy_protocol_endpoints: Vec<String>,
+}
+
+/// The `{sni}_{port}` directory entries for TLS SNI passthrough always use
+/// this port, since the TLS ClientHello carries no port information.
+static TLS_SNI_DIRECTORY_PORT: u16 = 443;
+
+/// Upper bound on the bytes buffered while waiting for a complete TLS
+/// ClientHello record, so a legitimately-incomplete (but never-finished)
+/// record can't grow the buffer without bound.
+static MAX_TLS_CLIENT_HELLO_BUFFER: usize = 16384;
+
+/// How long each read of the ClientHello may wait for more bytes, since
+/// this endpoint is reachable directly from untrusted clients and a silent
+/// one would otherwise park its task forever.
+static TLS_CLIENT_HELLO_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Load `user:password` credentials from an auth file into a lookup map.
+fn load_credentials(path: &str) -> Result<HashMap<String, String>, Error> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(user, password)| (String::from(user), String::from(password)))
+        .collect())
 }
 
 enum SocketEndpoint {
-    UnixSocketEndpoint(String),
-    TcpSocketEndpoint(SocketAddr),
+    Unix(String),
+    Tcp(SocketAddr),
+    Kcp(SocketAddr),
+}
+
+/// Where a CONNECT request for a matching domain should be dispatched.
+#[derive(Debug, Clone, Copy)]
+enum RouteTarget {
+    /// Connect to `{domain}_{port}` in the socket directory (the original behavior).
+    UnixDirectory,
+    /// Connect directly to `domain:port` over TCP.
+    TcpConnect,
+    /// Refuse the request.
+    Deny,
+}
+
+/// One `--route` rule: a domain pattern and the target it dispatches to.
+struct Route {
+    pattern: String,
+    target: RouteTarget,
+}
+
+/// Parse a `PATTERN=TARGET` routing rule from the command line.
+fn parse_route(raw: &str) -> Result<Route, Error> {
+    let (pattern, target) = raw
+        .split_once('=')
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "expected PATTERN=TARGET"))?;
+    let target = match target {
+        "unix-dir" => RouteTarget::UnixDirectory,
+        "tcp-connect" => RouteTarget::TcpConnect,
+        "deny" => RouteTarget::Deny,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown route target {}", target),
+            ))
+        }
+    };
+    Ok(Route {
+        pattern: String::from(pattern),
+        target,
+    })
+}
+
+/// Whether `domain` matches a route `pattern`.
+///
+/// `*` matches everything; a pattern starting with `*` matches any domain
+/// ending with the remaining suffix (e.g. `*.onion`); anything else must
+/// match the domain exactly.
+fn route_matches(pattern: &str, domain: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.strip_prefix('*') {
+        Some(suffix) => domain.ends_with(suffix),
+        None => pattern == domain,
+    }
 }
 
 struct ProxyService {
@@ -85,6 +272,14 @@ struct ProxyService {
     directory: String,
     /// Optional allow-list for user IDs.
     allowed_uids: Option<HashSet<uid_t>>,
+    /// Optional `user:password` credentials for SOCKS5 username/password authentication.
+    credentials: Option<HashMap<String, String>>,
+    /// Upstream routing table, evaluated in order.
+    routes: Vec<Route>,
+    /// TCP addresses of SNI-based TLS passthrough endpoints.
+    tls_sni_endpoints: Vec<SocketAddr>,
+    /// TCP addresses expecting a leading PROXY protocol v2 header.
+    proxy_protocol_endpoints: HashSet<SocketAddr>,
     cancellation_token: CancellationToken,
     tracker: TaskTracker,
 }
@@ -102,6 +297,15 @@ impl ProxyService {
             },
         }
     }
+
+    /// Find the route target for `domain`, or `RouteTarget::Deny` if nothing matches.
+    fn route_for(&self, domain: &str) -> RouteTarget {
+        self.routes
+            .iter()
+            .find(|route| route_matches(&route.pattern, domain))
+            .map(|route| route.target)
+            .unwrap_or(RouteTarget::Deny)
+    }
 }
 
 async fn send_reply<T: AsyncWrite + Unpin>(
@@ -123,6 +327,114 @@ async fn send_reply<T: AsyncWrite + Unpin>(
     socket.write_all(&reply).await
 }
 
+/// Send a SOCKS5 reply whose bound-address field carries `address`, as used
+/// for UDP ASSOCIATE (the relay's own address) and the RESOLVE commands.
+async fn send_reply_with_address<T: AsyncWrite + Unpin>(
+    socket: &mut T,
+    reply: u8,
+    address: SocketAddr,
+) -> Result<(), std::io::Error> {
+    let mut buffer = vec![SOCKS_VERSION5, reply, 0];
+    match address {
+        SocketAddr::V4(a) => {
+            buffer.push(AddressType::V4 as u8);
+            buffer.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            buffer.push(AddressType::V6 as u8);
+            buffer.extend_from_slice(&a.ip().octets());
+        }
+    }
+    buffer.extend_from_slice(&address.port().to_be_bytes());
+    socket.write_all(&buffer).await
+}
+
+/// Send a SOCKS5 reply whose bound-address field carries a domain name, as
+/// used for the RESOLVE_PTR reply.
+async fn send_reply_with_domain<T: AsyncWrite + Unpin>(
+    socket: &mut T,
+    reply: u8,
+    domain: &str,
+) -> Result<(), std::io::Error> {
+    // `domain` comes from an untrusted directory resolver backend, not a
+    // spec-bounded source, so it may not fit the one-byte length prefix.
+    if domain.len() > u8::MAX as usize {
+        debug!("SOCKS reply, resolved domain too long to encode");
+        return send_reply(socket, REP_HOST_NOT_REACHABLE).await;
+    }
+
+    let mut buffer = vec![
+        SOCKS_VERSION5,
+        reply,
+        0,
+        AddressType::DOMAINNAME as u8,
+        domain.len() as u8,
+    ];
+    buffer.extend_from_slice(domain.as_bytes());
+    buffer.extend_from_slice(&0u16.to_be_bytes());
+    socket.write_all(&buffer).await
+}
+
+/// Name of the well-known resolver Unix datagram socket looked up in
+/// `directory` to serve the RESOLVE and RESOLVE_PTR commands.
+static RESOLVER_SOCKET_NAME: &str = "__resolve__";
+
+/// How long to wait for a reply from a directory backend before giving up.
+/// Without this, a backend that never replies would park its task forever,
+/// which in turn keeps `tracker.wait()` from ever completing on shutdown.
+static BACKEND_RECV_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Resolve `domain` to an address via the directory's resolver socket.
+async fn resolve_domain(proxy_service: &Arc<ProxyService>, domain: &str) -> Result<IpAddr, Error> {
+    let resolver_path = Path::new((*proxy_service).directory.as_str()).join(RESOLVER_SOCKET_NAME);
+    let backend = UnixDatagram::unbound()?;
+    backend.connect(&resolver_path)?;
+
+    let mut request = vec![1u8];
+    request.extend_from_slice(domain.as_bytes());
+    backend.send(&request).await?;
+
+    let mut buffer: [u8; 16] = [0; 16];
+    let length = match timeout(BACKEND_RECV_TIMEOUT, backend.recv(&mut buffer)).await {
+        Ok(res) => res?,
+        Err(_) => return Err(Error::from(ErrorKind::TimedOut)),
+    };
+    match length {
+        4 => {
+            let mut raw: [u8; 4] = [0; 4];
+            raw.copy_from_slice(&buffer[..4]);
+            Ok(IpAddr::V4(Ipv4Addr::from(raw)))
+        }
+        16 => {
+            let mut raw: [u8; 16] = [0; 16];
+            raw.copy_from_slice(&buffer[..16]);
+            Ok(IpAddr::V6(Ipv6Addr::from(raw)))
+        }
+        _ => Err(Error::from(ErrorKind::Other)),
+    }
+}
+
+/// Resolve `address` to a domain name via the directory's resolver socket.
+async fn resolve_ptr(proxy_service: &Arc<ProxyService>, address: &IpAddr) -> Result<String, Error> {
+    let resolver_path = Path::new((*proxy_service).directory.as_str()).join(RESOLVER_SOCKET_NAME);
+    let backend = UnixDatagram::unbound()?;
+    backend.connect(&resolver_path)?;
+
+    let mut request = vec![2u8];
+    match address {
+        IpAddr::V4(a) => request.extend_from_slice(&a.octets()),
+        IpAddr::V6(a) => request.extend_from_slice(&a.octets()),
+    }
+    backend.send(&request).await?;
+
+    let mut buffer: [u8; 256] = [0; 256];
+    let length = match timeout(BACKEND_RECV_TIMEOUT, backend.recv(&mut buffer)).await {
+        Ok(res) => res?,
+        Err(_) => return Err(Error::from(ErrorKind::TimedOut)),
+    };
+    String::from_utf8(buffer[..length].to_vec()).map_err(|_| Error::from(ErrorKind::Other))
+}
+
 fn is_acceptable_hostname(address: &str) -> bool {
     return !(address.contains('/')
         || address.contains('\\')
@@ -130,6 +442,28 @@ fn is_acceptable_hostname(address: &str) -> bool {
         || address.contains('\0'));
 }
 
+/// Dispatch a CONNECT request for `domain:port` to its configured upstream,
+/// per the routing table in `proxy_service`.
+async fn connect_upstream(
+    proxy_service: &Arc<ProxyService>,
+    domain: &str,
+    port: u16,
+) -> Result<Box<dyn GenericStream>, Error> {
+    match proxy_service.route_for(domain) {
+        RouteTarget::Deny => Err(Error::from(ErrorKind::PermissionDenied)),
+        RouteTarget::UnixDirectory => {
+            let socket_filename = format!("{}_{}", domain, port);
+            let socket_path = Path::new((*proxy_service).directory.as_str()).join(socket_filename);
+            let stream = UnixStream::connect(socket_path).await?;
+            Ok(Box::new(stream))
+        }
+        RouteTarget::TcpConnect => {
+            let stream = TcpStream::connect((domain, port)).await?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
 async fn serve_socks<T: AsyncRead + AsyncWrite + Unpin>(
     proxy_service: Arc<ProxyService>,
     mut socket: T,
@@ -141,15 +475,48 @@ async fn serve_socks<T: AsyncRead + AsyncWrite + Unpin>(
             return Err(err);
         }
     };
-    if !methods.contains(&NO_AUTHENTICATION) {
-        info!("SOCKS reply no acceptable authentication");
-        let response: [u8; 2] = [SOCKS_VERSION5, NO_ACCEPTABLE_AUTHENTICATION.to_u8()];
-        socket.write_all(&response).await?;
-        return Ok(());
+
+    match &proxy_service.credentials {
+        Some(credentials) => {
+            if !methods.contains(&USERNAME_PASSWORD_AUTHENTICATION) {
+                info!("SOCKS reply no acceptable authentication");
+                let response: [u8; 2] = [SOCKS_VERSION5, NO_ACCEPTABLE_AUTHENTICATION.to_u8()];
+                socket.write_all(&response).await?;
+                return Ok(());
+            }
+
+            let response: [u8; 2] = [SOCKS_VERSION5, USERNAME_PASSWORD_AUTHENTICATION.to_u8()];
+            socket.write_all(&response).await?;
+
+            let (username, password) = match read_username_password(&mut socket).await {
+                Ok(res) => res,
+                Err(err) => {
+                    debug!("Could not read SOCKS username/password");
+                    return Err(err);
+                }
+            };
+
+            let authenticated = credentials.get(&username) == Some(&password);
+            let status: u8 = if authenticated { 0 } else { 1 };
+            socket.write_all(&[1, status]).await?;
+            if !authenticated {
+                info!("SOCKS authentication failed");
+                return Ok(());
+            }
+        }
+        None => {
+            if !methods.contains(&NO_AUTHENTICATION) {
+                info!("SOCKS reply no acceptable authentication");
+                let response: [u8; 2] = [SOCKS_VERSION5, NO_ACCEPTABLE_AUTHENTICATION.to_u8()];
+                socket.write_all(&response).await?;
+                return Ok(());
+            }
+
+            let response: [u8; 2] = [SOCKS_VERSION5, NO_AUTHENTICATION.to_u8()];
+            socket.write_all(&response).await?;
+        }
     }
 
-    let response: [u8; 2] = [SOCKS_VERSION5, NO_AUTHENTICATION.to_u8()];
-    socket.write_all(&response).await?;
     let request = match read_socks_request(&mut socket).await {
         Err(err) => {
             debug!("Could not read SOCKS request");
@@ -160,6 +527,58 @@ async fn serve_socks<T: AsyncRead + AsyncWrite + Unpin>(
 
     info!("{}", request);
 
+    if request.command == COMMAND_UDP_ASSOCIATE {
+        return serve_udp_associate(proxy_service, socket).await;
+    }
+
+    if request.command == COMMAND_RESOLVE {
+        let requested_domain = match request.address {
+            SocksRequestAddress::DomainName(r) => r,
+            _ => {
+                info!("SOCKS reply, address type not supported)");
+                send_reply(&mut socket, REP_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+                return Ok(());
+            }
+        };
+        if !is_acceptable_hostname(&requested_domain) {
+            info!("SOCKS reply, connection not allowed (invalid domain name)");
+            send_reply(&mut socket, REP_CONNECTION_NOT_ALLOWED).await?;
+            return Ok(());
+        }
+        return match resolve_domain(&proxy_service, &requested_domain).await {
+            Ok(address) => {
+                info!("SOCKS reply, succeeded (resolved {})", address);
+                send_reply_with_address(&mut socket, REP_SUCCEEDED, SocketAddr::new(address, 0))
+                    .await
+            }
+            Err(_) => {
+                info!("SOCKS reply, not reachable");
+                send_reply(&mut socket, REP_HOST_NOT_REACHABLE).await
+            }
+        };
+    }
+
+    if request.command == COMMAND_RESOLVE_PTR {
+        let requested_address = match request.address {
+            SocksRequestAddress::IpAddress(a) => a,
+            _ => {
+                info!("SOCKS reply, address type not supported)");
+                send_reply(&mut socket, REP_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+                return Ok(());
+            }
+        };
+        return match resolve_ptr(&proxy_service, &requested_address).await {
+            Ok(domain) => {
+                info!("SOCKS reply, succeeded (resolved {})", domain);
+                send_reply_with_domain(&mut socket, REP_SUCCEEDED, &domain).await
+            }
+            Err(_) => {
+                info!("SOCKS reply, not reachable");
+                send_reply(&mut socket, REP_HOST_NOT_REACHABLE).await
+            }
+        };
+    }
+
     if request.command != COMMAND_CONNECT {
         info!("SOCKS reply, command not supported");
         send_reply(&mut socket, REP_COMMAND_NOT_SUPPORTED).await?;
@@ -181,10 +600,14 @@ async fn serve_socks<T: AsyncRead + AsyncWrite + Unpin>(
         return Ok(());
     }
 
-    let socket_filename = format!("{}_{}", requested_domain, request.port);
-    let socket_path = Path::new(&(*proxy_service).directory.as_str()).join(socket_filename);
-    let mut remote_socket = match UnixStream::connect(socket_path).await {
+    let mut remote_socket = match connect_upstream(&proxy_service, &requested_domain, request.port).await
+    {
         Ok(res) => res,
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            info!("SOCKS reply, connection not allowed (denied by route)");
+            send_reply(&mut socket, REP_CONNECTION_NOT_ALLOWED).await?;
+            return Ok(());
+        }
         Err(_) => {
             info!("SOCKS reply, not reachable");
             send_reply(&mut socket, REP_HOST_NOT_REACHABLE).await?;
@@ -201,6 +624,130 @@ async fn serve_socks<T: AsyncRead + AsyncWrite + Unpin>(
     Ok(())
 }
 
+/// Relay one UDP ASSOCIATE datagram to its directory backend and send the
+/// reply, if any, back to the client through `udp_socket`.
+async fn forward_udp_datagram(
+    proxy_service: Arc<ProxyService>,
+    udp_socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    datagram: Vec<u8>,
+) {
+    let (header, payload) = match parse_udp_datagram(&datagram) {
+        Ok(res) => res,
+        Err(_) => {
+            debug!("Could not parse SOCKS UDP request");
+            return;
+        }
+    };
+
+    let requested_domain = match &header.address {
+        SocksRequestAddress::DomainName(r) => r,
+        _ => {
+            debug!("SOCKS UDP reply, address type not supported");
+            return;
+        }
+    };
+
+    if !is_acceptable_hostname(requested_domain) {
+        debug!("SOCKS UDP reply, connection not allowed (invalid domain name)");
+        return;
+    }
+
+    let socket_filename = format!("{}_{}", requested_domain, header.port);
+    let socket_path = Path::new((*proxy_service).directory.as_str()).join(socket_filename);
+
+    let backend = match UnixDatagram::unbound() {
+        Ok(res) => res,
+        Err(_) => return,
+    };
+    if backend.connect(&socket_path).is_err() {
+        debug!("SOCKS UDP reply, not reachable");
+        return;
+    }
+    if backend.send(payload).await.is_err() {
+        return;
+    }
+
+    let mut reply_buffer: [u8; 65507] = [0; 65507];
+    let reply_length = match timeout(BACKEND_RECV_TIMEOUT, backend.recv(&mut reply_buffer)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(_)) | Err(_) => return,
+    };
+
+    let mut reply = build_udp_datagram_header(&header.address, header.port);
+    reply.extend_from_slice(&reply_buffer[..reply_length]);
+    let _ = udp_socket.send_to(&reply, client_addr).await;
+}
+
+/// Serve a SOCKS5 UDP ASSOCIATE session.
+///
+/// Binds a UDP relay socket and reports its address to the client, then
+/// forwards datagrams between the client and the directory backend named by
+/// each datagram's header, until the TCP control connection closes.
+///
+/// The relay socket's address is known to anyone who can observe or guess it,
+/// so the first datagram's source address is latched as the session's
+/// client, and every later datagram from a different source is dropped —
+/// otherwise any host on the network could reflect traffic off an arbitrary
+/// `directory` backend without ever completing the SOCKS handshake.
+async fn serve_udp_associate<T: AsyncRead + AsyncWrite + Unpin>(
+    proxy_service: Arc<ProxyService>,
+    mut socket: T,
+) -> Result<(), Error> {
+    let udp_socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(res) => res,
+        Err(_) => {
+            info!("SOCKS reply, not reachable");
+            send_reply(&mut socket, REP_HOST_NOT_REACHABLE).await?;
+            return Ok(());
+        }
+    };
+    let udp_socket = Arc::new(udp_socket);
+    let local_addr = udp_socket.local_addr()?;
+
+    info!("SOCKS reply, succeeded (UDP associate on {})", local_addr);
+    send_reply_with_address(&mut socket, REP_SUCCEEDED, local_addr).await?;
+
+    let mut datagram_buffer: [u8; 65507] = [0; 65507];
+    let mut control_buffer: [u8; 1] = [0; 1];
+    let mut client_addr: Option<SocketAddr> = None;
+    loop {
+        tokio::select! {
+            _ = proxy_service.cancellation_token.cancelled() => {
+                break;
+            },
+            control_read = socket.read(&mut control_buffer) => {
+                match control_read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                }
+            },
+            received = udp_socket.recv_from(&mut datagram_buffer) => {
+                let (datagram_length, datagram_addr) = match received {
+                    Ok(res) => res,
+                    Err(_) => break,
+                };
+                match client_addr {
+                    None => client_addr = Some(datagram_addr),
+                    Some(expected) if expected != datagram_addr => {
+                        debug!("SOCKS UDP datagram dropped (source mismatch)");
+                        continue;
+                    }
+                    _ => {}
+                }
+                let proxy_service2 = proxy_service.clone();
+                let udp_socket2 = udp_socket.clone();
+                let datagram = Vec::from(&datagram_buffer[..datagram_length]);
+                proxy_service.tracker.spawn(async move {
+                    forward_udp_datagram(proxy_service2, udp_socket2, datagram_addr, datagram).await;
+                });
+            },
+        }
+    }
+
+    Ok(())
+}
+
 #[instrument(skip(proxy_service, socket))]
 async fn handle_socks_connection<T: AsyncRead + AsyncWrite + Unpin>(
     proxy_service: Arc<ProxyService>,
@@ -212,27 +759,159 @@ async fn handle_socks_connection<T: AsyncRead + AsyncWrite + Unpin>(
     }
 }
 
-fn make_service() -> ProxyService {
+/// Read a leading PROXY protocol v2 header off `socket` to recover the real
+/// client identity from behind a load balancer, then serve it as a normal
+/// SOCKS connection.
+#[instrument(skip(proxy_service, socket))]
+async fn handle_proxied_tcp_connection(proxy_service: Arc<ProxyService>, mut socket: TcpStream) {
+    let header = match read_proxy_protocol_header(&mut socket).await {
+        Ok(res) => res,
+        Err(err) => {
+            debug!("Could not read PROXY protocol header: {}", err);
+            return;
+        }
+    };
+    if let Some(source) = header.source {
+        info!("PROXY protocol recovered client source {}", source);
+    }
+
+    let socket = ProxiedTcpStream {
+        inner: socket,
+        unix_credentials: header.unix_credentials,
+    };
+
+    if !proxy_service.check_allowed_socket(&socket) {
+        debug!("Connection rejected");
+        return;
+    }
+
+    handle_socks_connection(proxy_service, socket).await;
+}
+
+/// Serve an SNI-based TLS passthrough connection.
+///
+/// Peeks the TLS ClientHello without consuming it from the logical stream
+/// (buffer-and-replay), extracts the SNI `server_name`, and routes the byte
+/// stream to the Unix socket `{sni}_{TLS_SNI_DIRECTORY_PORT}` in the socket
+/// directory, prepending the buffered ClientHello bytes to the upstream
+/// write. The `--route` table used by the SOCKS CONNECT path is not
+/// consulted.
+async fn serve_tls_sni(proxy_service: &Arc<ProxyService>, socket: &mut TcpStream) -> Result<(), Error> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut chunk: [u8; 4096] = [0; 4096];
+    let sni = loop {
+        let read_count = match timeout(TLS_CLIENT_HELLO_READ_TIMEOUT, socket.read(&mut chunk)).await {
+            Ok(res) => res?,
+            Err(_) => return Err(Error::from(ErrorKind::TimedOut)),
+        };
+        if read_count == 0 {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        buffer.extend_from_slice(&chunk[..read_count]);
+        if buffer.len() > MAX_TLS_CLIENT_HELLO_BUFFER {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        match parse_client_hello_sni(&buffer) {
+            Ok(Some(name)) => break name,
+            Ok(None) => continue,
+            Err(err) => return Err(err),
+        }
+    };
+
+    if !is_acceptable_hostname(&sni) {
+        debug!("TLS SNI connection rejected (invalid domain name)");
+        return Ok(());
+    }
+
+    let socket_filename = format!("{}_{}", sni, TLS_SNI_DIRECTORY_PORT);
+    let socket_path = Path::new((*proxy_service).directory.as_str()).join(socket_filename);
+    let mut remote_socket = match UnixStream::connect(socket_path).await {
+        Ok(res) => res,
+        Err(_) => {
+            debug!("TLS SNI backend not reachable");
+            return Ok(());
+        }
+    };
+
+    remote_socket.write_all(&buffer).await?;
+
+    let _ = copy_bidirectional(socket, &mut remote_socket).await;
+    Ok(())
+}
+
+#[instrument(skip(proxy_service, socket))]
+async fn handle_tls_sni_connection(proxy_service: Arc<ProxyService>, mut socket: TcpStream) {
+    debug!("New TLS SNI connection");
+    if let Err(err) = serve_tls_sni(&proxy_service, &mut socket).await {
+        debug!(error = display(err));
+    }
+}
+
+fn make_service() -> Result<ProxyService, Error> {
     let args = CliArguments::parse();
-    return ProxyService {
-        socket_endpoints: args
-            .sockets
-            .into_iter()
-            .map(|endpoint| {
+    let credentials = match args.auth_file {
+        None => None,
+        Some(path) => Some(load_credentials(&path)?),
+    };
+    let routes = if args.routes.is_empty() {
+        vec![Route {
+            pattern: String::from("*"),
+            target: RouteTarget::UnixDirectory,
+        }]
+    } else {
+        args.routes
+            .iter()
+            .map(|raw| parse_route(raw))
+            .collect::<Result<Vec<Route>, Error>>()?
+    };
+    let tls_sni_endpoints = args
+        .tls_sni_endpoints
+        .iter()
+        .map(|endpoint| {
+            endpoint
+                .parse()
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid --tls-sni-endpoint"))
+        })
+        .collect::<Result<Vec<SocketAddr>, Error>>()?;
+    let proxy_protocol_endpoints = args
+        .proxy_protocol_endpoints
+        .iter()
+        .map(|endpoint| {
+            endpoint
+                .parse()
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid --proxy-protocol endpoint"))
+        })
+        .collect::<Result<HashSet<SocketAddr>, Error>>()?;
+    let socket_endpoints = args
+        .sockets
+        .into_iter()
+        .map(|endpoint| match endpoint.strip_prefix("kcp://") {
+            Some(address) => address
+                .parse()
+                .map(SocketEndpoint::Kcp)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid kcp:// endpoint")),
+            None => {
                 let parsed: Result<std::net::SocketAddr, AddrParseError> = endpoint.parse();
-                match parsed {
-                    Err(_) => SocketEndpoint::UnixSocketEndpoint(endpoint),
-                    Ok(a) => SocketEndpoint::TcpSocketEndpoint(a),
-                }
-            })
-            .collect(),
+                Ok(match parsed {
+                    Err(_) => SocketEndpoint::Unix(endpoint),
+                    Ok(a) => SocketEndpoint::Tcp(a),
+                })
+            }
+        })
+        .collect::<Result<Vec<SocketEndpoint>, Error>>()?;
+    return Ok(ProxyService {
+        socket_endpoints,
         directory: args.directory,
         allowed_uids: args
             .allowed_uids
             .map(|allowed_uids| HashSet::from_iter(allowed_uids.into_iter())),
+        credentials,
+        routes,
+        tls_sni_endpoints,
+        proxy_protocol_endpoints,
         cancellation_token: CancellationToken::new(),
         tracker: TaskTracker::new(),
-    };
+    });
 }
 
 async fn accept_unix_socks_connections(proxy_service: Arc<ProxyService>, listener: UnixListener) {
@@ -263,7 +942,11 @@ async fn accept_unix_socks_connections(proxy_service: Arc<ProxyService>, listene
     proxy_service.tracker.close();
 }
 
-async fn accept_tcp_socks_connections(proxy_service: Arc<ProxyService>, listener: TcpListener) {
+async fn accept_tcp_socks_connections(
+    proxy_service: Arc<ProxyService>,
+    listener: TcpListener,
+    expect_proxy_protocol: bool,
+) {
     loop {
         tokio::select! {
             _ = proxy_service.cancellation_token.cancelled() => {
@@ -275,6 +958,14 @@ async fn accept_tcp_socks_connections(proxy_service: Arc<ProxyService>, listener
                     Ok(res) => res
                 };
 
+                if expect_proxy_protocol {
+                    let proxy_service3 = proxy_service.clone();
+                    proxy_service.tracker.spawn(async move {
+                        handle_proxied_tcp_connection(proxy_service3, socket).await;
+                    });
+                    continue;
+                }
+
                 if !proxy_service.check_allowed_socket(&socket) {
                     debug!("Connection rejected");
                     continue;
@@ -307,9 +998,92 @@ async fn start_tcp_socket(
     address: SocketAddr,
 ) -> Result<(), Error> {
     let listener = TcpListener::bind(address).await?;
+    let expect_proxy_protocol = proxy_service.proxy_protocol_endpoints.contains(&address);
+    let proxy_service2 = proxy_service.clone();
+    proxy_service.tracker.spawn(async move {
+        let _ = accept_tcp_socks_connections(proxy_service2, listener, expect_proxy_protocol).await;
+    });
+    Ok(())
+}
+
+async fn accept_kcp_socks_connections(proxy_service: Arc<ProxyService>, mut listener: KcpListener) {
+    loop {
+        tokio::select! {
+            _ = proxy_service.cancellation_token.cancelled() => {
+                break;
+            },
+            accepted = listener.accept() => {
+                let (socket, _) = match accepted {
+                    Err(_) => break,
+                    Ok(res) => res
+                };
+
+                if !proxy_service.check_allowed_socket(&socket) {
+                    debug!("Connection rejected");
+                    continue;
+                }
+
+                let proxy_service3 = proxy_service.clone();
+                proxy_service.tracker.spawn(async move {
+                    handle_socks_connection(proxy_service3, socket).await;
+                });
+            }
+        }
+    }
+    proxy_service.cancellation_token.cancel();
+    proxy_service.tracker.close();
+}
+
+async fn start_kcp_socket(
+    proxy_service: &Arc<ProxyService>,
+    address: SocketAddr,
+) -> Result<(), Error> {
+    let listener = KcpListener::bind(KcpConfig::default(), address)
+        .await
+        .map_err(Error::other)?;
     let proxy_service2 = proxy_service.clone();
     proxy_service.tracker.spawn(async move {
-        let _ = accept_tcp_socks_connections(proxy_service2, listener).await;
+        let _ = accept_kcp_socks_connections(proxy_service2, listener).await;
+    });
+    Ok(())
+}
+
+async fn accept_tls_sni_connections(proxy_service: Arc<ProxyService>, listener: TcpListener) {
+    loop {
+        tokio::select! {
+            _ = proxy_service.cancellation_token.cancelled() => {
+                break;
+            },
+            listened = listener.accept() => {
+                let (socket, _) = match listened {
+                    Err(_) => break,
+                    Ok(res) => res
+                };
+
+                if !proxy_service.check_allowed_socket(&socket) {
+                    debug!("Connection rejected");
+                    continue;
+                }
+
+                let proxy_service3 = proxy_service.clone();
+                proxy_service.tracker.spawn(async move {
+                    handle_tls_sni_connection(proxy_service3, socket).await;
+                });
+            }
+        }
+    }
+    proxy_service.cancellation_token.cancel();
+    proxy_service.tracker.close();
+}
+
+async fn start_tls_sni_endpoint(
+    proxy_service: &Arc<ProxyService>,
+    address: SocketAddr,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).await?;
+    let proxy_service2 = proxy_service.clone();
+    proxy_service.tracker.spawn(async move {
+        let _ = accept_tls_sni_connections(proxy_service2, listener).await;
     });
     Ok(())
 }
@@ -367,8 +1141,14 @@ fn handle_socket_activation(proxy_service: &Arc<ProxyService>) -> Result<(), Err
         match listeners? {
             AnyListener::Tcp(listener) => {
                 info!("Listening to TCP socket #{}", fd);
+                let expect_proxy_protocol = listener
+                    .local_addr()
+                    .map(|address| proxy_service.proxy_protocol_endpoints.contains(&address))
+                    .unwrap_or(false);
                 proxy_service.tracker.spawn(async move {
-                    let _ = accept_tcp_socks_connections(proxy_service2, listener).await;
+                    let _ =
+                        accept_tcp_socks_connections(proxy_service2, listener, expect_proxy_protocol)
+                            .await;
                 })
             }
             AnyListener::Unix(listener) => {
@@ -385,7 +1165,7 @@ fn handle_socket_activation(proxy_service: &Arc<ProxyService>) -> Result<(), Err
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let proxy_service = make_service();
+    let proxy_service = make_service()?;
     let proxy_service = Arc::new(proxy_service);
 
     tracing_subscriber::fmt::init();
@@ -394,17 +1174,26 @@ async fn main() -> Result<(), Error> {
 
     for socket_enpoint in &(*proxy_service).socket_endpoints {
         match socket_enpoint {
-            SocketEndpoint::UnixSocketEndpoint(path) => {
+            SocketEndpoint::Unix(path) => {
                 info!("Listening to Unix domain socket {}", path);
                 start_unix_socket(&proxy_service, path.as_str()).await?
             }
-            SocketEndpoint::TcpSocketEndpoint(address) => {
+            SocketEndpoint::Tcp(address) => {
                 info!("Listening to TCP domain socket {}", *address);
                 start_tcp_socket(&proxy_service, *address).await?
             }
+            SocketEndpoint::Kcp(address) => {
+                info!("Listening to KCP socket {}", *address);
+                start_kcp_socket(&proxy_service, *address).await?
+            }
         }
     }
 
+    for address in &(*proxy_service).tls_sni_endpoints {
+        info!("Listening to TLS SNI endpoint {}", *address);
+        start_tls_sni_endpoint(&proxy_service, *address).await?;
+    }
+
     if proxy_service.tracker.is_empty() {
         return Err(Error::from(ErrorKind::Other));
     }
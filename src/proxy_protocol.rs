@@ -0,0 +1,211 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use libc::{gid_t, uid_t};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The 12-byte magic that opens every PROXY protocol v2 header.
+static SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+static ADDRESS_FAMILY_INET: u8 = 0x1;
+static ADDRESS_FAMILY_INET6: u8 = 0x2;
+static ADDRESS_FAMILY_UNIX: u8 = 0x3;
+
+/// Length of the two null-padded path fields in an AF_UNIX address block.
+static UNIX_ADDRESS_BLOCK_LENGTH: usize = 216;
+
+/// TLV type carrying the peer's `(uid, gid)` for AF_UNIX connections, as
+/// forwarded by this proxy's own upstream load balancers. `0xE0` falls in
+/// the range the PROXY protocol spec reserves for private use.
+static TLV_TYPE_UNIX_CREDENTIALS: u8 = 0xE0;
+
+/// Unix peer credentials recovered from a PROXY protocol v2 TLV.
+#[derive(Debug, Clone, Copy)]
+pub struct UnixCredentials {
+    pub uid: uid_t,
+    #[allow(dead_code)]
+    pub gid: gid_t,
+}
+
+/// The client identity recovered from a PROXY protocol v2 header.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyProtocolHeader {
+    /// The real client address, for AF_INET/AF_INET6 connections.
+    pub source: Option<SocketAddr>,
+    /// The real client's Unix credentials, for AF_UNIX connections that
+    /// carry the private `TLV_TYPE_UNIX_CREDENTIALS` TLV.
+    pub unix_credentials: Option<UnixCredentials>,
+}
+
+fn parse_unix_credentials_tlv(tlvs: &[u8]) -> Option<UnixCredentials> {
+    let mut offset = 0;
+    while offset + 3 <= tlvs.len() {
+        let tlv_type = tlvs[offset];
+        let tlv_length = usize::from(u16::from_be_bytes([tlvs[offset + 1], tlvs[offset + 2]]));
+        let value_start = offset + 3;
+        if tlvs.len() < value_start + tlv_length {
+            return None;
+        }
+        if tlv_type == TLV_TYPE_UNIX_CREDENTIALS && tlv_length == 8 {
+            let value = &tlvs[value_start..(value_start + tlv_length)];
+            return Some(UnixCredentials {
+                uid: u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                gid: u32::from_be_bytes([value[4], value[5], value[6], value[7]]),
+            });
+        }
+        offset = value_start + tlv_length;
+    }
+    None
+}
+
+/// Read and parse a PROXY protocol v2 header from the start of a connection.
+///
+/// Reads exactly the 16-byte fixed preamble followed by the address block
+/// and TLVs whose length it declares, so no bytes belonging to the
+/// connection's real payload (e.g. a SOCKS client hello) are consumed.
+///
+/// The header is trusted verbatim: whoever is on the other end of `read`
+/// gets to claim any source address and, via the private
+/// `TLV_TYPE_UNIX_CREDENTIALS` TLV, any uid/gid. Callers must only invoke
+/// this on connections that can only originate from a trusted load
+/// balancer, never directly from untrusted clients.
+pub async fn read_proxy_protocol_header<T: AsyncRead + Unpin>(
+    read: &mut T,
+) -> Result<ProxyProtocolHeader> {
+    let mut preamble: [u8; 16] = [0; 16];
+    read.read_exact(&mut preamble).await?;
+
+    if preamble[0..12] != SIGNATURE {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+
+    let version_command = preamble[12];
+    if version_command >> 4 != 2 {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    let command = version_command & 0x0F;
+    let family = preamble[13] >> 4;
+    let length = usize::from(u16::from_be_bytes([preamble[14], preamble[15]]));
+
+    let mut address_block = vec![0u8; length];
+    read.read_exact(&mut address_block).await?;
+
+    // A LOCAL command (e.g. a health check) carries no address; keep using
+    // the connection's own peer address.
+    if command == 0 {
+        return Ok(ProxyProtocolHeader::default());
+    }
+
+    if family == ADDRESS_FAMILY_INET {
+        if address_block.len() < 12 {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        let mut raw: [u8; 4] = [0; 4];
+        raw.copy_from_slice(&address_block[0..4]);
+        let source_port = u16::from_be_bytes([address_block[8], address_block[9]]);
+        return Ok(ProxyProtocolHeader {
+            source: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(raw)), source_port)),
+            unix_credentials: None,
+        });
+    }
+
+    if family == ADDRESS_FAMILY_INET6 {
+        if address_block.len() < 36 {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        let mut raw: [u8; 16] = [0; 16];
+        raw.copy_from_slice(&address_block[0..16]);
+        let source_port = u16::from_be_bytes([address_block[32], address_block[33]]);
+        return Ok(ProxyProtocolHeader {
+            source: Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(raw)), source_port)),
+            unix_credentials: None,
+        });
+    }
+
+    if family == ADDRESS_FAMILY_UNIX {
+        if address_block.len() < UNIX_ADDRESS_BLOCK_LENGTH {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        return Ok(ProxyProtocolHeader {
+            source: None,
+            unix_credentials: parse_unix_credentials_tlv(&address_block[UNIX_ADDRESS_BLOCK_LENGTH..]),
+        });
+    }
+
+    // Unrecognized address family (AF_UNSPEC or reserved): no address to recover.
+    Ok(ProxyProtocolHeader::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version_command: u8, family_protocol: u8, address_block: &[u8]) -> Vec<u8> {
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.push(version_command);
+        bytes.push(family_protocol);
+        bytes.extend_from_slice(&(address_block.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(address_block);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn parses_an_inet_source_address() {
+        let mut address_block = Vec::new();
+        address_block.extend_from_slice(&[127, 0, 0, 1]); // source
+        address_block.extend_from_slice(&[10, 0, 0, 1]); // destination
+        address_block.extend_from_slice(&12345u16.to_be_bytes()); // source port
+        address_block.extend_from_slice(&443u16.to_be_bytes()); // destination port
+
+        let bytes = header(0x21, ADDRESS_FAMILY_INET << 4, &address_block);
+        let mut cursor = std::io::Cursor::new(bytes);
+        let parsed = read_proxy_protocol_header(&mut cursor).await.unwrap();
+        assert_eq!(
+            parsed.source,
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 12345))
+        );
+    }
+
+    #[tokio::test]
+    async fn local_command_carries_no_address() {
+        let bytes = header(0x20, 0, &[]);
+        let mut cursor = std::io::Cursor::new(bytes);
+        let parsed = read_proxy_protocol_header(&mut cursor).await.unwrap();
+        assert!(parsed.source.is_none());
+        assert!(parsed.unix_credentials.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_signature() {
+        let mut bytes = header(0x21, ADDRESS_FAMILY_INET << 4, &[0u8; 12]);
+        bytes[0] = 0;
+        let mut cursor = std::io::Cursor::new(bytes);
+        assert!(read_proxy_protocol_header(&mut cursor).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_an_inet_address_block_shorter_than_its_fields() {
+        let bytes = header(0x21, ADDRESS_FAMILY_INET << 4, &[0u8; 4]);
+        let mut cursor = std::io::Cursor::new(bytes);
+        assert!(read_proxy_protocol_header(&mut cursor).await.is_err());
+    }
+
+    #[test]
+    fn recovers_unix_credentials_from_the_private_tlv() {
+        let mut tlv = vec![TLV_TYPE_UNIX_CREDENTIALS, 0, 8];
+        tlv.extend_from_slice(&1000u32.to_be_bytes());
+        tlv.extend_from_slice(&1000u32.to_be_bytes());
+
+        let credentials = parse_unix_credentials_tlv(&tlv).unwrap();
+        assert_eq!(credentials.uid, 1000);
+        assert_eq!(credentials.gid, 1000);
+    }
+
+    #[test]
+    fn ignores_a_truncated_tlv() {
+        let tlv = vec![TLV_TYPE_UNIX_CREDENTIALS, 0, 8, 1, 2, 3];
+        assert!(parse_unix_credentials_tlv(&tlv).is_none());
+    }
+}
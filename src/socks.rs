@@ -25,6 +25,10 @@ pub struct SocksCommand(u8);
 pub static COMMAND_CONNECT: SocksCommand = SocksCommand(1);
 pub static COMMAND_BIND: SocksCommand = SocksCommand(2);
 pub static COMMAND_UDP_ASSOCIATE: SocksCommand = SocksCommand(3);
+/// Tor-style extension: resolve a domain name to an address (tokio-socks `RESOLVE`).
+pub static COMMAND_RESOLVE: SocksCommand = SocksCommand(0xF0);
+/// Tor-style extension: resolve an address to a domain name (tokio-socks `RESOLVE_PTR`).
+pub static COMMAND_RESOLVE_PTR: SocksCommand = SocksCommand(0xF1);
 
 pub static REP_SUCCEEDED: u8 = 0;
 pub static REP_CONNECTION_NOT_ALLOWED: u8 = 2;
@@ -41,8 +45,12 @@ impl SocksCommand {
 }
 
 pub static NO_AUTHENTICATION: AuthenticationMethod = AuthenticationMethod(0);
+pub static USERNAME_PASSWORD_AUTHENTICATION: AuthenticationMethod = AuthenticationMethod(2);
 pub static NO_ACCEPTABLE_AUTHENTICATION: AuthenticationMethod = AuthenticationMethod(255);
 
+/// Version byte expected in the RFC 1929 username/password sub-negotiation.
+pub static USERNAME_PASSWORD_VERSION: u8 = 1;
+
 pub async fn read_client_hello<T: AsyncRead + AsyncWrite + Unpin>(
     read: &mut T,
 ) -> Result<Vec<AuthenticationMethod>> {
@@ -72,6 +80,49 @@ pub async fn read_client_hello<T: AsyncRead + AsyncWrite + Unpin>(
     return Err(Error::from(ErrorKind::Other));
 }
 
+/// Read an RFC 1929 username/password sub-negotiation request.
+///
+/// Returns the `(username, password)` pair once a complete request (version
+/// byte, `ULEN`, username, `PLEN`, password) has been read.
+pub async fn read_username_password<T: AsyncRead + AsyncWrite + Unpin>(
+    read: &mut T,
+) -> Result<(String, String)> {
+    let mut buffer: [u8; 513] = [0; 513];
+    let mut total_read: usize = 0;
+    loop {
+        let read_count = read.read(&mut buffer[total_read..]).await?;
+        if read_count == 0 {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        total_read += read_count;
+        if total_read < 2 {
+            continue;
+        }
+
+        let version = buffer[0];
+        if version != USERNAME_PASSWORD_VERSION {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        let username_length = usize::from(buffer[1]);
+        if total_read < username_length + 3 {
+            continue;
+        }
+
+        let password_length = usize::from(buffer[2 + username_length]);
+        if total_read < username_length + password_length + 3 {
+            continue;
+        }
+
+        let username = str::from_utf8(&buffer[2..(2 + username_length)])
+            .map_err(|_| Error::from(ErrorKind::Other))?;
+        let password_offset = 3 + username_length;
+        let password = str::from_utf8(&buffer[password_offset..(password_offset + password_length)])
+            .map_err(|_| Error::from(ErrorKind::Other))?;
+
+        return Ok((String::from(username), String::from(password)));
+    }
+}
+
 #[repr(u8)]
 pub enum AddressType {
     V4 = 1,
@@ -99,6 +150,10 @@ fn get_command_name(command: SocksCommand) -> &'static str {
         return "BIND";
     } else if command == COMMAND_UDP_ASSOCIATE {
         return "UDP_ASSOCIATE";
+    } else if command == COMMAND_RESOLVE {
+        return "RESOLVE";
+    } else if command == COMMAND_RESOLVE_PTR {
+        return "RESOLVE_PTR";
     } else {
         return "?";
     }
@@ -196,3 +251,94 @@ pub async fn read_socks_request<T: AsyncRead + AsyncWrite + Unpin>(
     }
     return Err(Error::from(ErrorKind::Other));
 }
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct UdpRequestHeader {
+    pub address: SocksRequestAddress,
+    pub port: u16,
+}
+
+/// Parse the SOCKS5 UDP request header prefixed to every UDP ASSOCIATE datagram.
+///
+/// Returns the header together with the remaining payload slice. Datagrams
+/// announcing a fragment (the `FRAG` byte is non-zero) are rejected, as
+/// fragmentation is not supported.
+pub fn parse_udp_datagram(datagram: &[u8]) -> Result<(UdpRequestHeader, &[u8])> {
+    if datagram.len() < 4 {
+        return Err(Error::from(ErrorKind::Other));
+    }
+
+    let fragment = datagram[2];
+    if fragment != 0 {
+        return Err(Error::from(ErrorKind::Other));
+    }
+
+    let atype = datagram[3];
+    let (address, offset): (SocksRequestAddress, usize) = if atype == AddressType::DOMAINNAME as u8
+    {
+        if datagram.len() < 5 {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        let domain_length = usize::from(datagram[4]);
+        if datagram.len() < domain_length + 5 {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        let raw_address = str::from_utf8(&datagram[5..(5 + domain_length)])
+            .map_err(|_| Error::from(ErrorKind::Other))?;
+        (
+            SocksRequestAddress::DomainName(String::from(raw_address)),
+            5 + domain_length,
+        )
+    } else if atype == AddressType::V4 as u8 {
+        if datagram.len() < 8 {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        let mut raw_address: [u8; 4] = [0; 4];
+        raw_address.copy_from_slice(&datagram[4..8]);
+        (
+            SocksRequestAddress::IpAddress(IpAddr::V4(Ipv4Addr::from(raw_address))),
+            8,
+        )
+    } else if atype == AddressType::V6 as u8 {
+        if datagram.len() < 20 {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        let mut raw_address: [u8; 16] = [0; 16];
+        raw_address.copy_from_slice(&datagram[4..20]);
+        (
+            SocksRequestAddress::IpAddress(IpAddr::V6(Ipv6Addr::from(raw_address))),
+            20,
+        )
+    } else {
+        return Err(Error::from(ErrorKind::Other));
+    };
+
+    if datagram.len() < offset + 2 {
+        return Err(Error::from(ErrorKind::Other));
+    }
+    let port = u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+
+    Ok((UdpRequestHeader { address, port }, &datagram[(offset + 2)..]))
+}
+
+/// Build the SOCKS5 UDP request header to prepend to a relayed reply datagram.
+pub fn build_udp_datagram_header(address: &SocksRequestAddress, port: u16) -> Vec<u8> {
+    let mut header = vec![0u8, 0u8, 0u8];
+    match address {
+        SocksRequestAddress::IpAddress(IpAddr::V4(a)) => {
+            header.push(AddressType::V4 as u8);
+            header.extend_from_slice(&a.octets());
+        }
+        SocksRequestAddress::IpAddress(IpAddr::V6(a)) => {
+            header.push(AddressType::V6 as u8);
+            header.extend_from_slice(&a.octets());
+        }
+        SocksRequestAddress::DomainName(domain) => {
+            header.push(AddressType::DOMAINNAME as u8);
+            header.push(domain.len() as u8);
+            header.extend_from_slice(domain.as_bytes());
+        }
+    }
+    header.extend_from_slice(&port.to_be_bytes());
+    header
+}
@@ -0,0 +1,203 @@
+use std::io::{Error, ErrorKind, Result};
+use std::str;
+
+/// Handshake content type for a TLS record (RFC 8446 §5.1).
+static CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+/// Handshake message type for a ClientHello (RFC 8446 §4).
+static HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+/// Extension type for `server_name` (RFC 6066 §3).
+static EXTENSION_TYPE_SERVER_NAME: u16 = 0x0000;
+/// Name type for a DNS host name within the `server_name` extension.
+static SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+
+fn read_u16(buffer: &[u8], offset: usize) -> Result<u16> {
+    if buffer.len() < offset + 2 {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    Ok(u16::from_be_bytes([buffer[offset], buffer[offset + 1]]))
+}
+
+/// Extract the `HostName` entry from a `server_name` extension body.
+fn parse_server_name_extension(extension: &[u8]) -> Result<String> {
+    let list_length = usize::from(read_u16(extension, 0)?);
+    if extension.len() < 2 + list_length {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+
+    let mut offset = 2;
+    while offset + 3 <= extension.len() {
+        let name_type = extension[offset];
+        let name_length = usize::from(read_u16(extension, offset + 1)?);
+        let name_start = offset + 3;
+        if extension.len() < name_start + name_length {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        if name_type == SERVER_NAME_TYPE_HOST_NAME {
+            return str::from_utf8(&extension[name_start..(name_start + name_length)])
+                .map(String::from)
+                .map_err(|_| Error::from(ErrorKind::InvalidData));
+        }
+        offset = name_start + name_length;
+    }
+    Err(Error::from(ErrorKind::NotFound))
+}
+
+/// Parse the SNI `server_name` from a buffered TLS ClientHello record.
+///
+/// `buffer` is the bytes read from the connection so far, starting at the
+/// record header. Returns `Ok(None)` while more bytes are needed to
+/// complete the record (the caller should read more and retry with the same
+/// buffer, unmodified, plus the new bytes), `Err` if the data is not a TLS
+/// handshake ClientHello or has no `server_name` extension, and
+/// `Ok(Some(name))` once the host name has been recovered. Only the first
+/// record is parsed: a ClientHello spanning several records is an `Err`.
+pub fn parse_client_hello_sni(buffer: &[u8]) -> Result<Option<String>> {
+    if buffer.len() < 5 {
+        return Ok(None);
+    }
+    if buffer[0] != CONTENT_TYPE_HANDSHAKE {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    let record_length = usize::from(read_u16(buffer, 3)?);
+    if buffer.len() < 5 + record_length {
+        return Ok(None);
+    }
+    let handshake = &buffer[5..(5 + record_length)];
+
+    if handshake.len() < 4 {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    if handshake[0] != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    let handshake_length =
+        u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+    // `handshake` is already the full, fully-buffered record (checked above),
+    // so a `handshake_length` that doesn't fit inside it is not "need more
+    // bytes" — it's an inconsistent, malformed ClientHello.
+    if handshake.len() < 4 + handshake_length {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    let body = &handshake[4..(4 + handshake_length)];
+
+    // client_version (2 bytes) + random (32 bytes)
+    let mut offset = 34;
+    if body.len() < offset + 1 {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+
+    let session_id_length = usize::from(body[offset]);
+    offset += 1 + session_id_length;
+
+    let cipher_suites_length = usize::from(read_u16(body, offset)?);
+    offset += 2 + cipher_suites_length;
+
+    if body.len() < offset + 1 {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    let compression_methods_length = usize::from(body[offset]);
+    offset += 1 + compression_methods_length;
+
+    if offset == body.len() {
+        return Err(Error::from(ErrorKind::NotFound));
+    }
+
+    let extensions_length = usize::from(read_u16(body, offset)?);
+    offset += 2;
+    if body.len() < offset + extensions_length {
+        return Err(Error::from(ErrorKind::InvalidData));
+    }
+    let extensions = &body[offset..(offset + extensions_length)];
+
+    let mut extension_offset = 0;
+    while extension_offset + 4 <= extensions.len() {
+        let extension_type = read_u16(extensions, extension_offset)?;
+        let extension_length = usize::from(read_u16(extensions, extension_offset + 2)?);
+        let extension_start = extension_offset + 4;
+        if extensions.len() < extension_start + extension_length {
+            return Err(Error::from(ErrorKind::InvalidData));
+        }
+        if extension_type == EXTENSION_TYPE_SERVER_NAME {
+            return parse_server_name_extension(
+                &extensions[extension_start..(extension_start + extension_length)],
+            )
+            .map(Some);
+        }
+        extension_offset = extension_start + extension_length;
+    }
+
+    Err(Error::from(ErrorKind::NotFound))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal TLS record carrying a ClientHello with a single
+    /// `server_name` extension for `hostname`.
+    fn client_hello_with_sni(hostname: &str) -> Vec<u8> {
+        let mut server_name_list = vec![SERVER_NAME_TYPE_HOST_NAME];
+        server_name_list.extend_from_slice(&(hostname.len() as u16).to_be_bytes());
+        server_name_list.extend_from_slice(hostname.as_bytes());
+
+        let mut server_name_extension = (server_name_list.len() as u16).to_be_bytes().to_vec();
+        server_name_extension.extend_from_slice(&server_name_list);
+
+        let mut extensions = EXTENSION_TYPE_SERVER_NAME.to_be_bytes().to_vec();
+        extensions.extend_from_slice(&(server_name_extension.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&server_name_extension);
+
+        let mut body = vec![0u8; 34]; // client_version + random
+        body.push(0); // session_id_length
+        body.extend_from_slice(&0u16.to_be_bytes()); // cipher_suites_length
+        body.push(0); // compression_methods_length
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 3, 3];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn parses_sni_from_a_complete_record() {
+        let record = client_hello_with_sni("example.com");
+        assert_eq!(
+            parse_client_hello_sni(&record).unwrap(),
+            Some("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn needs_more_data_for_a_truncated_record() {
+        let record = client_hello_with_sni("example.com");
+        assert_eq!(parse_client_hello_sni(&record[..record.len() - 1]).unwrap(), None);
+    }
+
+    #[test]
+    fn needs_more_data_for_a_partial_record_header() {
+        assert_eq!(parse_client_hello_sni(&[CONTENT_TYPE_HANDSHAKE, 3, 3]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_a_handshake_length_that_does_not_fit_the_buffered_record() {
+        // The record is fully buffered (record_length matches what follows),
+        // but the ClientHello lies about its own handshake_length.
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 3, 3, 0, 10];
+        record.extend_from_slice(&[HANDSHAKE_TYPE_CLIENT_HELLO, 0xFF, 0xFF, 0xFF]);
+        record.extend_from_slice(&[0u8; 6]);
+        assert!(parse_client_hello_sni(&record).is_err());
+    }
+
+    #[test]
+    fn rejects_a_non_handshake_record() {
+        let mut record = vec![0x17, 3, 3, 0, 1]; // application data
+        record.push(0);
+        assert!(parse_client_hello_sni(&record).is_err());
+    }
+}